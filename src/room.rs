@@ -1,21 +1,25 @@
 //! Room state
 use std::{
-    cmp::{Ordering, PartialOrd},
-    collections::{hash_map::Entry, HashMap},
+    cmp::{Ordering, PartialOrd, Reverse},
+    collections::{hash_map::Entry, BTreeMap, BinaryHeap, HashMap},
     fmt,
 };
 
 use log::debug;
 use ndarray::{Array, Ix2};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use screeps_api::{
     endpoints::room_terrain::TerrainType,
     websocket::{
-        types::room::flags::Flag, types::room::objects::KnownRoomObject, RoomUpdate, RoomUserInfo,
+        types::room::flags::{Color as FlagColor, Flag},
+        types::room::objects::KnownRoomObject,
+        RoomUpdate, RoomUserInfo,
     },
     RoomName, RoomTerrain,
 };
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct RoomId {
     pub shard: Option<String>,
     pub room_name: RoomName,
@@ -57,6 +61,7 @@ pub struct Room {
     objects: HashMap<String, KnownRoomObject>,
     flags: Vec<Flag>,
     users: HashMap<String, RoomUserInfo>,
+    history: Option<History>,
 }
 
 impl Room {
@@ -69,14 +74,49 @@ impl Room {
             objects: HashMap::new(),
             flags: Vec::new(),
             users: HashMap::new(),
+            history: None,
         }
     }
 
+    /// Begin recording every applied [`RoomUpdate`] into an append-only
+    /// [`History`] so past ticks can be replayed via [`Room::snapshot_at`].
+    /// A full keyframe is kept every `keyframe_interval` ticks to bound replay
+    /// cost. Only updates applied after this call are captured, so enable it on
+    /// a freshly constructed room to record its full timeline.
+    pub fn enable_history(&mut self, keyframe_interval: u32) {
+        self.history = Some(History::new(keyframe_interval));
+    }
+
+    /// The recorded history, if recording has been enabled.
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Game tick of the most recently applied update, if any.
+    pub fn last_update_time(&self) -> Option<u32> {
+        self.last_update_time
+    }
+
+    /// The room this instance tracks.
+    pub fn room_id(&self) -> &RoomId {
+        &self.room
+    }
+
     pub fn update(&mut self, update: RoomUpdate) -> Result<(), serde_json::Error> {
         debug!("updating metadata");
-        if let Some(time) = update.game_time {
+        let game_time = update.game_time;
+        if let Some(time) = game_time {
             self.last_update_time = Some(time);
         }
+        // Snapshot the raw deltas before they are consumed below, so history
+        // replay re-applies exactly what `update` applied.
+        let recorded = self.history.as_ref().map(|_| {
+            (
+                update.objects.clone(),
+                update.flags.clone(),
+                update.users.clone(),
+            )
+        });
         debug!("updating objects");
         for (id, data) in update.objects.into_iter() {
             debug!(
@@ -121,11 +161,52 @@ impl Room {
             }
         }
 
+        if let (Some(history), Some((objects, flags, users))) = (self.history.as_mut(), recorded) {
+            // Carry the previous tick forward when the update omits game_time.
+            let tick = game_time.or(self.last_update_time).unwrap_or(0);
+            history.record(tick, objects, flags, users);
+        }
+
         debug!("update complete");
 
         Ok(())
     }
 
+    /// Reconstruct the room as of `game_time`, replaying the recorded history
+    /// from the nearest keyframe. Returns `None` if history is not enabled or
+    /// the requested tick predates the first recorded tick.
+    pub fn snapshot_at(&self, game_time: u32) -> Option<VisualRoom> {
+        let history = self.history.as_ref()?;
+        let state = history.state_at(game_time)?;
+
+        let mut room = VisualRoom::new(Some(game_time), self.room.clone());
+        for (row_idx, row) in self.terrain.terrain.iter().enumerate() {
+            for (col_idx, item) in row.iter().enumerate() {
+                if let Some(itt) = InterestingTerrainType::from_terrain(*item) {
+                    room.push_top(VisualObject::InterestingTerrain {
+                        x: col_idx as u32,
+                        y: row_idx as u32,
+                        ty: itt,
+                    });
+                }
+            }
+        }
+        for flag in state.flags {
+            room.push_top(VisualObject::Flag(flag));
+        }
+        for value in state.objects.into_values() {
+            // Skip objects that no longer deserialize rather than abort the
+            // whole snapshot.
+            if let Ok(obj) = serde_json::from_value(value) {
+                room.push_top(VisualObject::RoomObject(obj));
+            }
+        }
+        for list in room.objs.iter_mut() {
+            list.sort_unstable();
+        }
+        Some(room)
+    }
+
     pub fn visualize(&self) -> VisualRoom {
         let mut room = VisualRoom::new(self.last_update_time, self.room.clone());
 
@@ -155,6 +236,383 @@ impl Room {
 
         room
     }
+
+    /// Find a least-cost route from `from` to `to` over the 50x50 grid,
+    /// honouring Screeps movement costs: swamp is 5, plain is 2, a road is 1
+    /// (roads override the underlying terrain) and wall terrain is impassable.
+    ///
+    /// Returns the inclusive tile sequence from `from` to `to`, or `None` if no
+    /// route exists. Coordinates outside `0..=49` are rejected, and `from ==
+    /// to` yields a single-element path.
+    pub fn find_path(
+        &self,
+        from: (u32, u32),
+        to: (u32, u32),
+        opts: PathOpts,
+    ) -> Option<Vec<(u32, u32)>> {
+        let in_range = |(x, y): (u32, u32)| x < 50 && y < 50;
+        if !in_range(from) || !in_range(to) {
+            return None;
+        }
+        if from == to {
+            return Some(vec![from]);
+        }
+
+        // `base` is the terrain-only cost (objects ignored); `cost` overlays
+        // roads and obstacles. `None` means impassable.
+        let (base, cost) = self.cost_maps(opts);
+
+        // The destination may itself hold a blocker (a creep parked on the goal
+        // tile); it is reachable as long as its terrain is passable.
+        let enter = |(x, y): (u32, u32)| -> Option<u32> {
+            let (x, y) = (x as usize, y as usize);
+            if (x, y) == (to.0 as usize, to.1 as usize) {
+                cost[[x, y]].or(base[[x, y]])
+            } else {
+                cost[[x, y]]
+            }
+        };
+
+        // Minimum passable tile cost keeps the distance heuristic admissible.
+        // The destination's own entry cost counts too, since `enter` may fall
+        // back to its terrain cost.
+        let min_cost = cost
+            .iter()
+            .copied()
+            .chain(std::iter::once(enter(to)))
+            .flatten()
+            .min()
+            .unwrap_or(1)
+            .max(1);
+
+        let mut g = Array::from_elem((50, 50), u32::MAX);
+        let mut came_from: Array<Option<(u32, u32)>, Ix2> = Array::from_elem((50, 50), None);
+        let mut closed = Array::from_elem((50, 50), false);
+        let mut open = BinaryHeap::new();
+
+        g[[from.0 as usize, from.1 as usize]] = 0;
+        open.push(Reverse((heuristic(from, to, opts.diagonal) * min_cost, from)));
+
+        while let Some(Reverse((_, current))) = open.pop() {
+            if current == to {
+                return Some(reconstruct(&came_from, to));
+            }
+            // A node can be queued several times with improving `f`; expand the
+            // first (best) pop and skip the stale duplicates.
+            if std::mem::replace(&mut closed[[current.0 as usize, current.1 as usize]], true) {
+                continue;
+            }
+            let gc = g[[current.0 as usize, current.1 as usize]];
+            for next in neighbours(current, opts.diagonal) {
+                let step = match enter(next) {
+                    Some(c) => c,
+                    None => continue,
+                };
+                let tentative = gc.saturating_add(step);
+                if tentative < g[[next.0 as usize, next.1 as usize]] {
+                    came_from[[next.0 as usize, next.1 as usize]] = Some(current);
+                    g[[next.0 as usize, next.1 as usize]] = tentative;
+                    let f = tentative + heuristic(next, to, opts.diagonal) * min_cost;
+                    open.push(Reverse((f, next)));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Build the terrain-only and object-overlaid cost maps used by
+    /// [`Room::find_path`]. `None` entries are impassable.
+    fn cost_maps(&self, opts: PathOpts) -> (Array<Option<u32>, Ix2>, Array<Option<u32>, Ix2>) {
+        let mut base = Array::from_elem((50, 50), Some(2u32));
+        for (y, row) in self.terrain.terrain.iter().enumerate() {
+            for (x, item) in row.iter().enumerate() {
+                if x >= 50 || y >= 50 {
+                    continue;
+                }
+                base[[x, y]] = match item {
+                    TerrainType::Plains => Some(2),
+                    TerrainType::Swamp => Some(5),
+                    TerrainType::Wall | TerrainType::SwampyWall => None,
+                };
+            }
+        }
+
+        // A constructed wall is a hard obstacle like wall terrain: it blocks the
+        // tile even when it is the destination, so it belongs in `base`.
+        let mut cost = base.clone();
+        for obj in self.objects.values() {
+            let (x, y) = (obj.x() as usize, obj.y() as usize);
+            if x >= 50 || y >= 50 {
+                continue;
+            }
+            match RoomObjectType::of(obj) {
+                // Roads override the terrain cost wherever the tile is passable.
+                RoomObjectType::Road => {
+                    if cost[[x, y]].is_some() {
+                        cost[[x, y]] = Some(1);
+                    }
+                }
+                RoomObjectType::Wall => {
+                    base[[x, y]] = None;
+                    cost[[x, y]] = None;
+                }
+                ty if opts.obstacles && ty.blocks_movement() => cost[[x, y]] = None,
+                _ => {}
+            }
+        }
+
+        (base, cost)
+    }
+}
+
+/// Tuning knobs for [`Room::find_path`].
+#[derive(Copy, Clone, Debug, smart_default::SmartDefault)]
+pub struct PathOpts {
+    /// Allow diagonal steps between the eight neighbours of a tile.
+    #[default = true]
+    pub diagonal: bool,
+    /// Treat creeps and blocking structures as impassable.
+    #[default = true]
+    pub obstacles: bool,
+}
+
+/// Admissible distance estimate: Chebyshev when diagonals are allowed,
+/// Manhattan otherwise.
+fn heuristic(a: (u32, u32), b: (u32, u32), diagonal: bool) -> u32 {
+    let dx = a.0.abs_diff(b.0);
+    let dy = a.1.abs_diff(b.1);
+    if diagonal {
+        dx.max(dy)
+    } else {
+        dx + dy
+    }
+}
+
+/// In-bounds neighbours of `(x, y)` on the 50x50 grid.
+fn neighbours((x, y): (u32, u32), diagonal: bool) -> Vec<(u32, u32)> {
+    const ORTHO: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+    const DIAG: [(i32, i32); 4] = [(1, 1), (1, -1), (-1, 1), (-1, -1)];
+    let mut out = Vec::with_capacity(8);
+    let deltas: &[(i32, i32)] = if diagonal { &DIAG } else { &[] };
+    for &(dx, dy) in ORTHO.iter().chain(deltas) {
+        let nx = x as i32 + dx;
+        let ny = y as i32 + dy;
+        if (0..50).contains(&nx) && (0..50).contains(&ny) {
+            out.push((nx as u32, ny as u32));
+        }
+    }
+    out
+}
+
+/// Walk the `came_from` chain back from `to` into a forward-ordered path.
+fn reconstruct(came_from: &Array<Option<(u32, u32)>, Ix2>, to: (u32, u32)) -> Vec<(u32, u32)> {
+    let mut path = vec![to];
+    let mut cur = to;
+    while let Some(prev) = came_from[[cur.0 as usize, cur.1 as usize]] {
+        path.push(prev);
+        cur = prev;
+    }
+    path.reverse();
+    path
+}
+
+/// One recorded tick: the raw object/user deltas (null means delete) plus the
+/// full flag set, exactly as they arrived in the [`RoomUpdate`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TickDelta {
+    game_time: u32,
+    objects: HashMap<String, Value>,
+    flags: Vec<Flag>,
+    users: Option<HashMap<String, Value>>,
+}
+
+/// A full materialized object/user set captured every `keyframe_interval`
+/// ticks so a seek only has to replay the deltas since the nearest keyframe.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Keyframe {
+    game_time: u32,
+    objects: HashMap<String, Value>,
+    users: HashMap<String, Value>,
+    flags: Vec<Flag>,
+    /// Number of recorded deltas folded into this keyframe; replay resumes at
+    /// this index so deltas sharing the keyframe's tick are not dropped.
+    delta_count: usize,
+}
+
+/// The reconstructed contents of a room at a single tick.
+struct HistoryState {
+    objects: HashMap<String, Value>,
+    flags: Vec<Flag>,
+}
+
+/// An append-only log of applied [`RoomUpdate`]s keyed by `game_time`, able to
+/// reconstruct a room's state as of any recorded tick and to round-trip to
+/// disk.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct History {
+    keyframe_interval: u32,
+    keyframes: Vec<Keyframe>,
+    deltas: Vec<TickDelta>,
+    /// Running materialized object/user state, snapshotted into keyframes.
+    #[serde(skip)]
+    live_objects: HashMap<String, Value>,
+    #[serde(skip)]
+    live_users: HashMap<String, Value>,
+}
+
+impl History {
+    fn new(keyframe_interval: u32) -> Self {
+        History {
+            keyframe_interval: keyframe_interval.max(1),
+            keyframes: Vec::new(),
+            deltas: Vec::new(),
+            live_objects: HashMap::new(),
+            live_users: HashMap::new(),
+        }
+    }
+
+    /// Append a tick, folding its deltas into the running state and cutting a
+    /// keyframe when the interval has elapsed.
+    fn record(
+        &mut self,
+        game_time: u32,
+        objects: HashMap<String, Value>,
+        flags: Vec<Flag>,
+        users: Option<HashMap<String, Value>>,
+    ) {
+        apply_delta(&mut self.live_objects, &objects);
+        if let Some(users) = &users {
+            apply_delta(&mut self.live_users, users);
+        }
+
+        let due = match self.keyframes.last() {
+            None => true,
+            Some(kf) => game_time.saturating_sub(kf.game_time) >= self.keyframe_interval,
+        };
+
+        self.deltas.push(TickDelta {
+            game_time,
+            objects,
+            flags: flags.clone(),
+            users,
+        });
+
+        if due {
+            self.keyframes.push(Keyframe {
+                game_time,
+                objects: self.live_objects.clone(),
+                users: self.live_users.clone(),
+                flags,
+                delta_count: self.deltas.len(),
+            });
+        }
+    }
+
+    /// The inclusive `(first, last)` recorded tick range, or `None` if empty.
+    pub fn tick_range(&self) -> Option<(u32, u32)> {
+        match (self.deltas.first(), self.deltas.last()) {
+            (Some(first), Some(last)) => Some((first.game_time, last.game_time)),
+            _ => None,
+        }
+    }
+
+    /// Reconstruct the object set and flags as of `game_time` by replaying
+    /// forward from the nearest keyframe at or before it.
+    fn state_at(&self, game_time: u32) -> Option<HistoryState> {
+        let (first, _) = self.tick_range()?;
+        if game_time < first {
+            return None;
+        }
+
+        // Normally the first recorded tick always cuts a keyframe, but a
+        // corrupt or hand-edited log may have deltas without one.
+        let keyframe = self
+            .keyframes
+            .iter()
+            .rev()
+            .find(|kf| kf.game_time <= game_time)?;
+        let mut objects = keyframe.objects.clone();
+        let mut flags = keyframe.flags.clone();
+
+        // The keyframe already folds in the first `delta_count` deltas; replay
+        // only those recorded afterwards, up to the requested tick.
+        let start = keyframe.delta_count.min(self.deltas.len());
+        for delta in &self.deltas[start..] {
+            if delta.game_time > game_time {
+                break;
+            }
+            apply_delta(&mut objects, &delta.objects);
+            flags = delta.flags.clone();
+        }
+
+        Some(HistoryState { objects, flags })
+    }
+
+    /// Serialize the log to `path` as JSON.
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        let json = serde_json::to_vec(self).map_err(std::io::Error::other)?;
+        std::fs::write(path, json)
+    }
+
+    /// Load a log previously written by [`History::save`], rebuilding the
+    /// running state from the last keyframe and any trailing deltas.
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let mut history: History = serde_json::from_slice(&bytes).map_err(std::io::Error::other)?;
+        history.keyframe_interval = history.keyframe_interval.max(1);
+        let seed = history
+            .keyframes
+            .last()
+            .map(|kf| (kf.objects.clone(), kf.users.clone(), kf.delta_count));
+        if let Some((objects, users, delta_count)) = seed {
+            history.live_objects = objects;
+            history.live_users = users;
+            let start = delta_count.min(history.deltas.len());
+            for delta in &history.deltas[start..] {
+                apply_delta(&mut history.live_objects, &delta.objects);
+                if let Some(users) = &delta.users {
+                    apply_delta(&mut history.live_users, users);
+                }
+            }
+        }
+        Ok(history)
+    }
+}
+
+/// Fold a `RoomUpdate`-style delta into a materialized JSON object map: a null
+/// value deletes the id, an object value is recursively merged, anything else
+/// replaces it. Mirrors the null-means-delete semantics of [`Room::update`].
+fn apply_delta(state: &mut HashMap<String, Value>, delta: &HashMap<String, Value>) {
+    for (id, value) in delta {
+        if value.is_null() {
+            state.remove(id);
+        } else {
+            match state.get_mut(id) {
+                Some(existing) => merge_value(existing, value.clone()),
+                None => {
+                    state.insert(id.clone(), value.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Recursively merge `patch` into `target`; null members of an object delete
+/// the corresponding key, matching the Screeps websocket diff protocol.
+fn merge_value(target: &mut Value, patch: Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(&key);
+                } else {
+                    merge_value(target.entry(key).or_insert(Value::Null), value);
+                }
+            }
+        }
+        (target, patch) => *target = patch,
+    }
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone)]
@@ -202,6 +660,32 @@ impl RoomObjectType {
             PowerBank, PowerSpawn, Lab, Terminal, Nuker, Creep,
         )
     }
+
+    /// Whether a creep cannot share this tile with the object. Mirrors the
+    /// Screeps `OBSTACLE_OBJECT_TYPES` set: roads, containers, ramparts,
+    /// tombstones, resources, portals, extractors and keeper lairs are
+    /// walkable.
+    pub fn blocks_movement(&self) -> bool {
+        use RoomObjectType::*;
+        matches!(
+            self,
+            Wall | Source
+                | Mineral
+                | Controller
+                | Extension
+                | Spawn
+                | Link
+                | Storage
+                | Tower
+                | Observer
+                | PowerBank
+                | PowerSpawn
+                | Lab
+                | Terminal
+                | Nuker
+                | Creep
+        )
+    }
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone)]
@@ -334,4 +818,616 @@ impl VisualRoom {
             .expect("expected all objects to have valid coordinates (0-49)")
             .push(item);
     }
+
+    /// Whether the stack at `(x, y)` blocks line of sight: wall terrain, a wall
+    /// structure, or a rampart all count as opaque.
+    fn is_opaque(&self, x: usize, y: usize) -> bool {
+        self.objs[[x, y]].iter().any(|obj| match obj {
+            VisualObject::InterestingTerrain {
+                ty: InterestingTerrainType::Wall,
+                ..
+            } => true,
+            VisualObject::RoomObject(o) => matches!(
+                RoomObjectType::of(o),
+                RoomObjectType::Wall | RoomObjectType::Rampart
+            ),
+            _ => false,
+        })
+    }
+
+    /// Compute the set of tiles visible from `origin` within `radius`, treating
+    /// wall terrain, wall structures and ramparts as opaque.
+    ///
+    /// Uses recursive shadowcasting over the eight octants, so walls occlude
+    /// symmetrically. The circular cutoff is a squared-Euclidean distance test
+    /// and the origin tile is always visible. Out-of-range origins yield an
+    /// all-false grid.
+    pub fn visibility_from(&self, origin: (u32, u32), radius: u32) -> Array<bool, Ix2> {
+        let mut visible = Array::from_elem((50, 50), false);
+        let (ox, oy) = (origin.0 as i32, origin.1 as i32);
+        if ox < 0 || ox >= 50 || oy < 0 || oy >= 50 {
+            return visible;
+        }
+        visible[[ox as usize, oy as usize]] = true;
+        // No tile is further than 49 * sqrt(2) < 70 away on a 50x50 grid, so a
+        // larger radius cannot reveal anything extra and an unclamped cast
+        // risks overflowing the squared-distance cutoff.
+        let radius = radius.min(70) as i32;
+        for &(xx, xy, yx, yy) in &SHADOWCAST_OCTANTS {
+            self.cast_light(&mut visible, ox, oy, radius, 1, 1.0, 0.0, xx, xy, yx, yy);
+        }
+        visible
+    }
+
+    /// Like [`VisualRoom::visibility_from`], but returns the object stacks with
+    /// everything outside the visible set cleared, so a renderer can hide
+    /// occluded tiles.
+    pub fn visible_objects(
+        &self,
+        origin: (u32, u32),
+        radius: u32,
+    ) -> Array<Vec<VisualObject>, Ix2> {
+        let visible = self.visibility_from(origin, radius);
+        let mut out = Array::from_elem((50, 50), Vec::new());
+        for ((x, y), stack) in self.objs.indexed_iter() {
+            if visible[[x, y]] {
+                out[[x, y]] = stack.clone();
+            }
+        }
+        out
+    }
+
+    /// One octant of Björn Bergström's recursive shadowcasting. `(xx, xy, yx,
+    /// yy)` transforms octant-local coordinates into room coordinates so the
+    /// same outward row scan serves all eight octants.
+    #[allow(clippy::too_many_arguments)]
+    fn cast_light(
+        &self,
+        visible: &mut Array<bool, Ix2>,
+        cx: i32,
+        cy: i32,
+        radius: i32,
+        row: i32,
+        mut start_slope: f64,
+        end_slope: f64,
+        xx: i32,
+        xy: i32,
+        yx: i32,
+        yy: i32,
+    ) {
+        if start_slope < end_slope {
+            return;
+        }
+        let radius2 = radius * radius;
+        let mut next_start = start_slope;
+        for i in row..=radius {
+            let dy = -i;
+            let mut blocked = false;
+            for dx in -i..=0 {
+                let l_slope = (dx as f64 - 0.5) / (dy as f64 + 0.5);
+                let r_slope = (dx as f64 + 0.5) / (dy as f64 - 0.5);
+                if start_slope < r_slope {
+                    continue;
+                } else if end_slope > l_slope {
+                    break;
+                }
+                let ax = cx + dx * xx + dy * xy;
+                let ay = cy + dx * yx + dy * yy;
+                if ax < 0 || ax >= 50 || ay < 0 || ay >= 50 {
+                    continue;
+                }
+                if dx * dx + dy * dy <= radius2 {
+                    visible[[ax as usize, ay as usize]] = true;
+                }
+                let opaque = self.is_opaque(ax as usize, ay as usize);
+                if blocked {
+                    if opaque {
+                        next_start = r_slope;
+                    } else {
+                        blocked = false;
+                        start_slope = next_start;
+                    }
+                } else if opaque && i < radius {
+                    // Entering a wall after a transparent run: recurse into the
+                    // narrower interval beyond its leading edge.
+                    blocked = true;
+                    self.cast_light(
+                        visible, cx, cy, radius, i + 1, start_slope, l_slope, xx, xy, yx, yy,
+                    );
+                    next_start = r_slope;
+                }
+            }
+            if blocked {
+                break;
+            }
+        }
+    }
+}
+
+/// `(xx, xy, yx, yy)` coordinate transforms for the eight shadowcasting
+/// octants.
+const SHADOWCAST_OCTANTS: [(i32, i32, i32, i32); 8] = [
+    (1, 0, 0, 1),
+    (0, 1, 1, 0),
+    (0, -1, 1, 0),
+    (-1, 0, 0, 1),
+    (-1, 0, 0, -1),
+    (0, -1, -1, 0),
+    (0, 1, -1, 0),
+    (1, 0, 0, -1),
+];
+
+/// An inclusive rectangle of room coordinates used to scope a [`RoomQuery`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BoundingBox {
+    pub min_x: u32,
+    pub min_y: u32,
+    pub max_x: u32,
+    pub max_y: u32,
+}
+
+impl BoundingBox {
+    pub fn new(min_x: u32, min_y: u32, max_x: u32, max_y: u32) -> Self {
+        BoundingBox {
+            min_x,
+            min_y,
+            max_x,
+            max_y,
+        }
+    }
+
+    /// Whether `(x, y)` falls inside the rectangle (bounds inclusive).
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        (self.min_x..=self.max_x).contains(&x) && (self.min_y..=self.max_y).contains(&y)
+    }
+}
+
+/// Filter parameters for [`Room::query`]. Unset fields match everything, so
+/// `RoomQuery::default()` returns every object.
+#[derive(Clone, Debug, Default)]
+pub struct RoomQuery {
+    /// Match objects owned by the user with this username.
+    pub owner: Option<String>,
+    /// Restrict to these object types.
+    pub types: Option<Vec<RoomObjectType>>,
+    /// Restrict flags to those of this colour.
+    pub flagged_only: Option<FlagColor>,
+    /// Restrict to objects and flags within this rectangle.
+    pub within: Option<BoundingBox>,
+    /// Cap the number of returned objects.
+    pub limit: Option<usize>,
+}
+
+/// The objects and flags matching a [`RoomQuery`], each in the stable ordering
+/// used by [`VisualObject`].
+#[derive(Clone, Debug, Default)]
+pub struct RoomQueryResult {
+    pub objects: Vec<KnownRoomObject>,
+    pub flags: Vec<Flag>,
+}
+
+impl Room {
+    /// Search the room's contents with the given filter. Objects are matched on
+    /// owner, type and bounding box; flags on colour and bounding box. Results
+    /// come back in [`VisualObject`] order so output is deterministic.
+    pub fn query(&self, query: &RoomQuery) -> RoomQueryResult {
+        // Resolve the requested username to the set of owning user ids once, so
+        // the per-object test is a cheap membership check.
+        let owner_ids: Option<Vec<&str>> = query.owner.as_ref().map(|name| {
+            self.users
+                .iter()
+                .filter(|(_, info)| &info.username == name)
+                .map(|(id, _)| id.as_str())
+                .collect()
+        });
+
+        let mut objects: Vec<&KnownRoomObject> = self
+            .objects
+            .values()
+            .filter(|obj| {
+                // Cheap coordinate bound first, so a narrow box short-circuits
+                // before the type and ownership checks.
+                if let Some(bbox) = &query.within {
+                    if !bbox.contains(obj.x(), obj.y()) {
+                        return false;
+                    }
+                }
+                if let Some(types) = &query.types {
+                    if !types.contains(&RoomObjectType::of(obj)) {
+                        return false;
+                    }
+                }
+                if let Some(ids) = &owner_ids {
+                    match owner_of(obj) {
+                        Some(owner) => ids.iter().any(|id| *id == owner),
+                        None => false,
+                    }
+                } else {
+                    true
+                }
+            })
+            .collect();
+
+        objects.sort_unstable_by(|a, b| {
+            RoomObjectType::of(a)
+                .cmp(&RoomObjectType::of(b))
+                .then_with(|| a.id().cmp(b.id()))
+        });
+        if let Some(limit) = query.limit {
+            objects.truncate(limit);
+        }
+
+        let mut flags: Vec<Flag> = self
+            .flags
+            .iter()
+            .filter(|flag| {
+                if let Some(bbox) = &query.within {
+                    if !bbox.contains(flag.x, flag.y) {
+                        return false;
+                    }
+                }
+                match &query.flagged_only {
+                    Some(color) => &flag.color == color,
+                    None => true,
+                }
+            })
+            .cloned()
+            .collect();
+        flags.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        RoomQueryResult {
+            objects: objects.into_iter().cloned().collect(),
+            flags,
+        }
+    }
+}
+
+/// The owning user id of an object, if it has one, read from its serialized
+/// `user` field so every owned structure type is covered uniformly.
+fn owner_of(obj: &KnownRoomObject) -> Option<String> {
+    serde_json::to_value(obj)
+        .ok()?
+        .get("user")?
+        .as_str()
+        .map(str::to_owned)
+}
+
+/// A registry owning every tracked [`Room`], keyed by [`RoomId`].
+///
+/// Terrain arrives first and lazily constructs the room; subsequent
+/// `(RoomId, RoomUpdate)` pairs are routed to the matching entry. The registry
+/// also tracks per-room [`ConnectionState`], can evict rooms that have gone
+/// quiet for too many ticks, and surfaces the set of rooms whose state has
+/// advanced since the last redraw so a dashboard only repaints what changed.
+#[derive(Debug, Default)]
+pub struct World {
+    rooms: HashMap<RoomId, RoomEntry>,
+    /// Latest game tick observed across all rooms, used as the eviction clock.
+    game_time: Option<u32>,
+}
+
+#[derive(Debug)]
+struct RoomEntry {
+    room: Room,
+    state: ConnectionState,
+    /// `last_update_time` at the point this room was last drained via
+    /// [`World::take_dirty`]; `None` until it has been redrawn once.
+    drawn_at: Option<u32>,
+}
+
+impl World {
+    pub fn new() -> Self {
+        World::default()
+    }
+
+    /// Register terrain for a room, constructing its [`Room`] if this is the
+    /// first time it is seen. Terrain for an existing room is ignored, matching
+    /// the fact that terrain never changes during a game.
+    pub fn set_terrain(&mut self, room_id: RoomId, terrain: RoomTerrain) {
+        self.rooms.entry(room_id.clone()).or_insert_with(|| RoomEntry {
+            room: Room::new(room_id, terrain),
+            state: ConnectionState::default(),
+            drawn_at: None,
+        });
+    }
+
+    /// Route an update to the room it belongs to. Returns `Ok(false)` without
+    /// applying anything if terrain has not arrived for that room yet, since a
+    /// [`Room`] cannot be constructed without it.
+    pub fn update(
+        &mut self,
+        room_id: &RoomId,
+        update: RoomUpdate,
+    ) -> Result<bool, serde_json::Error> {
+        match self.rooms.get_mut(room_id) {
+            Some(entry) => {
+                entry.room.update(update)?;
+                if let Some(time) = entry.room.last_update_time() {
+                    self.game_time = Some(self.game_time.map_or(time, |g| g.max(time)));
+                }
+                Ok(true)
+            }
+            None => {
+                debug!("dropping update for untracked room {}", room_id);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Record the connection state for a room. No-op if the room is unknown.
+    pub fn set_connection_state(&mut self, room_id: &RoomId, state: ConnectionState) {
+        if let Some(entry) = self.rooms.get_mut(room_id) {
+            entry.state = state;
+        }
+    }
+
+    /// The connection state for a room, or [`ConnectionState::Disconnected`] if
+    /// it is not tracked.
+    pub fn connection_state(&self, room_id: &RoomId) -> ConnectionState {
+        self.rooms
+            .get(room_id)
+            .map(|e| e.state)
+            .unwrap_or_default()
+    }
+
+    /// Run `f` against a tracked room, returning its result, or `None` if the
+    /// room is unknown.
+    pub fn with_room<F, R>(&self, room_id: &RoomId, f: F) -> Option<R>
+    where
+        F: FnOnce(&Room) -> R,
+    {
+        self.rooms.get(room_id).map(|e| f(&e.room))
+    }
+
+    /// Mutable counterpart to [`World::with_room`].
+    pub fn with_room_mut<F, R>(&mut self, room_id: &RoomId, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut Room) -> R,
+    {
+        self.rooms.get_mut(room_id).map(|e| f(&mut e.room))
+    }
+
+    /// Find a room by name within a shard, ignoring the fact that callers may
+    /// not have a full [`RoomId`] on hand.
+    pub fn find(&self, shard: Option<&str>, room_name: RoomName) -> Option<&Room> {
+        self.rooms.iter().find_map(|(id, entry)| {
+            (id.room_name == room_name && id.shard.as_deref() == shard).then_some(&entry.room)
+        })
+    }
+
+    /// Iterate over every tracked room.
+    pub fn rooms(&self) -> impl Iterator<Item = (&RoomId, &Room)> {
+        self.rooms.iter().map(|(id, entry)| (id, &entry.room))
+    }
+
+    /// Number of tracked rooms.
+    pub fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+    }
+
+    /// Drop rooms that have received no update within `max_idle_ticks` of the
+    /// latest observed game tick, returning the ids that were evicted. Rooms
+    /// that have never reported a tick are never evicted.
+    pub fn evict_stale(&mut self, max_idle_ticks: u32) -> Vec<RoomId> {
+        let now = match self.game_time {
+            Some(now) => now,
+            None => return Vec::new(),
+        };
+        let stale: Vec<RoomId> = self
+            .rooms
+            .iter()
+            .filter(|(_, entry)| match entry.room.last_update_time() {
+                Some(last) => now.saturating_sub(last) > max_idle_ticks,
+                None => false,
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &stale {
+            self.rooms.remove(id);
+        }
+        stale
+    }
+
+    /// Return the rooms whose `last_update_time` has advanced since the last
+    /// call, marking them clean so the next call only reports fresh changes.
+    pub fn take_dirty(&mut self) -> Vec<RoomId> {
+        let mut dirty = Vec::new();
+        for (id, entry) in self.rooms.iter_mut() {
+            let last = entry.room.last_update_time();
+            if last != entry.drawn_at {
+                entry.drawn_at = last;
+                dirty.push(id.clone());
+            }
+        }
+        dirty
+    }
+}
+
+impl VisualRoom {
+    /// Render a concise spoken description of the room for a screen reader.
+    ///
+    /// Rather than a glyph grid, objects of the same type within
+    /// [`CLUSTER_RADIUS`] tiles of each other are collapsed into clusters
+    /// reported by count and centroid, coordinates are
+    /// named by their compass region, and — when `focus` is given — the topmost
+    /// object on that tile is called out (mirroring [`VisualRoom`] stacking).
+    pub fn describe(&self, focus: Option<(u32, u32)>) -> String {
+        let mut parts: Vec<String> = Vec::new();
+        parts.push(match self.last_update_time {
+            Some(tick) => format!("Room {} at tick {}.", self.room_id, tick),
+            None => format!("Room {}.", self.room_id),
+        });
+
+        // Group object coordinates by type; BTreeMap keeps the declared
+        // RoomObjectType order so the description is deterministic.
+        let mut by_type: BTreeMap<RoomObjectType, Vec<(u32, u32)>> = BTreeMap::new();
+        let mut flags = 0usize;
+        for stack in self.objs.iter() {
+            for obj in stack {
+                match obj {
+                    VisualObject::RoomObject(o) => by_type
+                        .entry(RoomObjectType::of(o))
+                        .or_default()
+                        .push((o.x(), o.y())),
+                    VisualObject::Flag(_) => flags += 1,
+                    VisualObject::InterestingTerrain { .. } => {}
+                }
+            }
+        }
+
+        for (ty, points) in &by_type {
+            let noun = type_noun(ty);
+            for cluster in cluster_points(points) {
+                let (cx, cy) = centroid(&cluster);
+                let region = region_of(cx, cy);
+                if cluster.len() == 1 {
+                    parts.push(format!("One {} at ({}, {}) in the {}.", noun, cx, cy, region));
+                } else {
+                    parts.push(format!(
+                        "{} {}s clustered near ({}, {}) in the {}.",
+                        cluster.len(),
+                        noun,
+                        cx,
+                        cy,
+                        region
+                    ));
+                }
+            }
+        }
+
+        if flags > 0 {
+            parts.push(format!(
+                "{} flag{}.",
+                flags,
+                if flags == 1 { "" } else { "s" }
+            ));
+        }
+
+        if parts.len() == 1 {
+            parts.push("Nothing notable.".to_owned());
+        }
+
+        if let Some((fx, fy)) = focus {
+            let focused = self
+                .objs
+                .get([fx as usize, fy as usize])
+                .and_then(|stack| stack.last());
+            match focused {
+                Some(obj) => parts.push(format!(
+                    "At ({}, {}): {}.",
+                    fx,
+                    fy,
+                    describe_object(obj)
+                )),
+                None => parts.push(format!("At ({}, {}): empty.", fx, fy)),
+            }
+        }
+
+        parts.join(" ")
+    }
+}
+
+/// How far apart two same-type objects may sit and still be reported as one
+/// cluster (Chebyshev distance), loose enough to catch checkerboard layouts.
+const CLUSTER_RADIUS: u32 = 2;
+
+/// Partition `points` into clusters of mutually near objects via depth-first
+/// flood fill over the [`CLUSTER_RADIUS`] neighbourhood.
+fn cluster_points(points: &[(u32, u32)]) -> Vec<Vec<(u32, u32)>> {
+    let mut visited = vec![false; points.len()];
+    let mut clusters = Vec::new();
+    for start in 0..points.len() {
+        if visited[start] {
+            continue;
+        }
+        let mut cluster = Vec::new();
+        let mut stack = vec![start];
+        visited[start] = true;
+        while let Some(i) = stack.pop() {
+            cluster.push(points[i]);
+            for (j, &p) in points.iter().enumerate() {
+                if !visited[j] && chebyshev(points[i], p) <= CLUSTER_RADIUS {
+                    visited[j] = true;
+                    stack.push(j);
+                }
+            }
+        }
+        clusters.push(cluster);
+    }
+    clusters
+}
+
+fn chebyshev(a: (u32, u32), b: (u32, u32)) -> u32 {
+    a.0.abs_diff(b.0).max(a.1.abs_diff(b.1))
+}
+
+/// Rounded mean position of a cluster.
+fn centroid(points: &[(u32, u32)]) -> (u32, u32) {
+    let n = points.len() as u32;
+    let sx: u32 = points.iter().map(|p| p.0).sum();
+    let sy: u32 = points.iter().map(|p| p.1).sum();
+    ((sx + n / 2) / n, (sy + n / 2) / n)
+}
+
+/// Name the nine compass regions of the 50x50 room.
+fn region_of(x: u32, y: u32) -> &'static str {
+    let band = |v: u32| if v < 17 { 0 } else if v < 34 { 1 } else { 2 };
+    match (band(x), band(y)) {
+        (0, 0) => "northwest",
+        (1, 0) => "north",
+        (2, 0) => "northeast",
+        (0, 1) => "west",
+        (1, 1) => "center",
+        (2, 1) => "east",
+        (0, 2) => "southwest",
+        (1, 2) => "south",
+        _ => "southeast",
+    }
+}
+
+/// A short spoken label for a single object.
+fn describe_object(obj: &VisualObject) -> String {
+    match obj {
+        VisualObject::InterestingTerrain { ty, .. } => match ty {
+            InterestingTerrainType::Swamp => "swamp".to_owned(),
+            InterestingTerrainType::Wall => "wall".to_owned(),
+        },
+        VisualObject::Flag(flag) => format!("flag {}", flag.name),
+        VisualObject::RoomObject(o) => type_noun(&RoomObjectType::of(o)).to_owned(),
+    }
+}
+
+/// The spoken noun for an object type.
+fn type_noun(ty: &RoomObjectType) -> &'static str {
+    use RoomObjectType::*;
+    match ty {
+        Road => "road",
+        Container => "container",
+        Tombstone => "tombstone",
+        Resource => "resource",
+        Rampart => "rampart",
+        Wall => "wall",
+        Source => "source",
+        Mineral => "mineral",
+        KeeperLair => "source-keeper lair",
+        Controller => "controller",
+        Extractor => "extractor",
+        Extension => "extension",
+        Spawn => "spawn",
+        Portal => "portal",
+        Link => "link",
+        Storage => "storage",
+        Tower => "tower",
+        Observer => "observer",
+        PowerBank => "power bank",
+        PowerSpawn => "power spawn",
+        Lab => "lab",
+        Terminal => "terminal",
+        Nuker => "nuker",
+        Creep => "creep",
+    }
 }